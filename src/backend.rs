@@ -0,0 +1,223 @@
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use futures::future;
+use futures::sync::mpsc;
+use futures::{Async, Future, Poll, Stream};
+use net2::UdpBuilder;
+use tokio::codec::BytesCodec;
+use tokio::net::{UdpFramed, UdpSocket};
+use tokio::reactor;
+use trust_dns_proto::multicast::{MdnsQueryType, MdnsStream};
+use trust_dns_proto::xfer::{BufStreamHandle, SerialMessage};
+
+use crate::task::Spawner;
+
+#[cfg(unix)]
+use net2::unix::UnixUdpBuilderExt;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_ADDRESS: &str = "224.0.0.251";
+
+pub const BROADCAST_PORT: u16 = 5354;
+
+pub type MessageStream = Box<dyn Stream<Item = SerialMessage, Error = Error>>;
+
+pub type MessageStreamFuture = Box<dyn Future<Item = MessageStream, Error = Error>>;
+
+// Carries raw DNS messages between peers of the local network, discovery
+// itself works the same on top of every backend
+pub trait DiscoveryBackend {
+    // Messages sent to this address reach all peers
+    fn group_addr(&self) -> SocketAddr;
+
+    // Returns the stream of incoming messages and a handle to send messages
+    fn open(&self, spawner: &Spawner) -> (MessageStreamFuture, BufStreamHandle);
+
+    // Whether a message sent to the source address of another message reaches
+    // the peer which sent it
+    fn supports_unicast(&self) -> bool {
+        true
+    }
+}
+
+// Multicast DNS, works on most networks and is what other implementations use
+pub struct MdnsBackend {
+    interface: Option<Ipv4Addr>,
+}
+
+impl MdnsBackend {
+    // Multicast packets are sent and received through the given interface
+    // instead of the OS default, needed on hosts with multiple interfaces
+    // (common on Windows and macOS)
+    pub fn new(interface: Option<Ipv4Addr>) -> MdnsBackend {
+        MdnsBackend { interface }
+    }
+
+    // Joins the multicast group on our interface. Allow multiple peers on the
+    // same host to listen on the same port
+    pub fn bind(&self) -> Result<std::net::UdpSocket, Error> {
+        let group_addr = self.group_addr();
+
+        let group = match group_addr.ip() {
+            IpAddr::V4(group) => group,
+            IpAddr::V6(_) => unreachable!(),
+        };
+
+        let builder = UdpBuilder::new_v4()?;
+        builder.reuse_address(true)?;
+
+        // Binding to the multicast address filters out unrelated packets on
+        // Unix, Windows only allows binding to the unspecified address
+        #[cfg(unix)]
+        let socket = {
+            builder.reuse_port(true)?;
+            builder.bind(group_addr)?
+        };
+
+        #[cfg(windows)]
+        let socket = builder.bind((Ipv4Addr::UNSPECIFIED, group_addr.port()))?;
+
+        socket.join_multicast_v4(&group, &self.interface.unwrap_or(Ipv4Addr::UNSPECIFIED))?;
+
+        Ok(socket)
+    }
+}
+
+impl DiscoveryBackend for MdnsBackend {
+    fn group_addr(&self) -> SocketAddr {
+        SocketAddr::new(MDNS_ADDRESS.parse().unwrap(), MDNS_PORT)
+    }
+
+    fn open(&self, _spawner: &Spawner) -> (MessageStreamFuture, BufStreamHandle) {
+        // The stream sends from a socket with a random port, which also
+        // receives unicast responses. It only selects the interface for
+        // outgoing packets and would join the group on the default interface,
+        // so we listen to the group on our own socket instead
+        let (mdns_stream, mdns_stream_sender) = MdnsStream::new(
+            self.group_addr(),
+            MdnsQueryType::OneShot,
+            Some(1),
+            self.interface,
+            None,
+        );
+
+        let multicast_socket = self.bind();
+
+        let mdns_stream = mdns_stream.and_then(move |stream| {
+            let multicast_socket =
+                UdpSocket::from_std(multicast_socket?, &reactor::Handle::default())?;
+
+            let multicast_stream = UdpFramed::new(multicast_socket, BytesCodec::new())
+                .map(|(bytes, source)| SerialMessage::new(bytes.to_vec(), source));
+
+            Ok(Box::new(stream.select(multicast_stream)) as MessageStream)
+        });
+
+        (Box::new(mdns_stream), mdns_stream_sender)
+    }
+}
+
+// Plain UDP broadcast on a fixed port, for networks which block multicast
+pub struct BroadcastBackend {
+    port: u16,
+}
+
+impl BroadcastBackend {
+    pub fn new(port: u16) -> BroadcastBackend {
+        BroadcastBackend { port }
+    }
+
+    // Allow multiple peers on the same host to listen on the same port
+    pub fn bind(&self) -> Result<std::net::UdpSocket, Error> {
+        let builder = UdpBuilder::new_v4()?;
+        builder.reuse_address(true)?;
+
+        #[cfg(unix)]
+        builder.reuse_port(true)?;
+
+        let socket = builder.bind((Ipv4Addr::UNSPECIFIED, self.port))?;
+        socket.set_broadcast(true)?;
+
+        Ok(socket)
+    }
+}
+
+impl DiscoveryBackend for BroadcastBackend {
+    fn group_addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::BROADCAST.into(), self.port)
+    }
+
+    fn open(&self, spawner: &Spawner) -> (MessageStreamFuture, BufStreamHandle) {
+        let (message_sender, outbound_messages) = mpsc::unbounded();
+        let message_sender = BufStreamHandle::new(message_sender);
+
+        let socket = self.bind();
+        let spawner = spawner.clone();
+
+        // Register the socket once the event loop is running
+        let broadcast_stream = future::result(socket).and_then(move |socket| {
+            let send_socket =
+                UdpSocket::from_std(socket.try_clone()?, &reactor::Handle::default())?;
+            let socket = UdpSocket::from_std(socket, &reactor::Handle::default())?;
+
+            spawner.spawn(SendMessages {
+                socket: send_socket,
+                messages: outbound_messages,
+                pending: None,
+            });
+
+            let stream = UdpFramed::new(socket, BytesCodec::new())
+                .map(|(bytes, source)| SerialMessage::new(bytes.to_vec(), source));
+
+            Ok(Box::new(stream) as MessageStream)
+        });
+
+        (Box::new(broadcast_stream), message_sender)
+    }
+
+    // Peers on the same host share the port, the kernel hands unicast packets
+    // to only one of them
+    fn supports_unicast(&self) -> bool {
+        false
+    }
+}
+
+// Sends outgoing messages until the sending side of the channel is gone. A
+// message which can't be sent, for example when there is no route to the
+// broadcast address, is dropped without stopping the ones after it
+struct SendMessages {
+    socket: UdpSocket,
+    messages: mpsc::UnboundedReceiver<SerialMessage>,
+    pending: Option<SerialMessage>,
+}
+
+impl Future for SendMessages {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Some(message) = self.pending.take() {
+                match self.socket.poll_send_to(message.bytes(), &message.addr()) {
+                    Ok(Async::Ready(_)) => (),
+                    Ok(Async::NotReady) => {
+                        self.pending = Some(message);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(err) => eprintln!(
+                        "Could not send discovery message to {}: {}",
+                        message.addr(),
+                        err
+                    ),
+                }
+            }
+
+            match self.messages.poll()? {
+                Async::Ready(Some(message)) => self.pending = Some(message),
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::Error;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use rand::Rng;
+use tokio::timer::Delay;
+
+// Packets which can be delayed at the same time, more have to wait for a slot
+const MAX_DELAYED: usize = 1024;
+
+// Artificial network trouble, used to reproduce bugs which only happen on
+// flaky networks and to test timeout logic end-to-end
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chaos {
+    latency: Duration,
+    drop_rate: f64,
+}
+
+impl Chaos {
+    pub fn new(latency: Duration, drop_rate: f64) -> Chaos {
+        Chaos { latency, drop_rate }
+    }
+
+    // Randomly drops items of a stream and delays every remaining one, each
+    // counted from its own arrival
+    pub fn wrap<S>(self, stream: S) -> impl Stream<Item = S::Item, Error = Error>
+    where
+        S: Stream<Error = Error>,
+    {
+        let Chaos { latency, drop_rate } = self;
+
+        stream
+            .filter(move |_| drop_rate == 0.0 || rand::thread_rng().gen::<f64>() >= drop_rate)
+            .map(move |item| {
+                if latency == Duration::from_millis(0) {
+                    Either::A(future::ok(item))
+                } else {
+                    Either::B(
+                        Delay::new(Instant::now() + latency)
+                            .map(move |_| item)
+                            .map_err(Error::other),
+                    )
+                }
+            })
+            .buffered(MAX_DELAYED)
+    }
+}
@@ -0,0 +1,313 @@
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::backend::BROADCAST_PORT;
+use crate::node::DEFAULT_PORT;
+use crate::relay::RELAY_PORT;
+
+const CONFIG_DIR_NAME: &str = "toy-hypercore";
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+// Length of discovery keys in bytes
+const DISCOVERY_KEY_LENGTH: usize = 32;
+
+// Seconds to wait for peers when cloning before giving up
+const DEFAULT_LOOKUP_TIMEOUT: u64 = 30;
+
+// Longer timeouts don't make sense and would overflow when added to an instant
+pub const MAX_LOOKUP_TIMEOUT: u64 = 86400;
+
+// How discovery messages are exchanged on the local network
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    Mdns,
+    // For networks which block multicast but allow broadcast
+    Broadcast,
+}
+
+impl DiscoveryMode {
+    pub fn parse(value: &str) -> Option<DiscoveryMode> {
+        match value {
+            "mdns" => Some(DiscoveryMode::Mdns),
+            "broadcast" => Some(DiscoveryMode::Broadcast),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    pub relay_port: u16,
+    pub interface: Option<Ipv4Addr>,
+    pub discovery: DiscoveryMode,
+    pub broadcast_port: u16,
+    // Seconds, 0 waits forever
+    pub lookup_timeout: u64,
+    // Hex encoded discovery keys we refuse to announce
+    pub denylist: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            port: DEFAULT_PORT,
+            relay_port: RELAY_PORT,
+            interface: None,
+            discovery: DiscoveryMode::Mdns,
+            broadcast_port: BROADCAST_PORT,
+            lookup_timeout: DEFAULT_LOOKUP_TIMEOUT,
+            denylist: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let contents = fs::read_to_string(path)?;
+
+        toml::from_str(&contents).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    // Load configuration from the default location, falling back to default
+    // values when there is no config file
+    pub fn load_default() -> Result<Config, Error> {
+        match Config::default_path() {
+            Some(ref path) if path.exists() => Config::load(path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    // Checks all values and returns a list of problems, naming the offending
+    // keys, when some are invalid
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        if self.port == 0 {
+            problems.push(String::from("port: must be between 1 and 65535"));
+        }
+
+        if self.relay_port == 0 {
+            problems.push(String::from("relay_port: must be between 1 and 65535"));
+        }
+
+        if self.broadcast_port == 0 {
+            problems.push(String::from("broadcast_port: must be between 1 and 65535"));
+        }
+
+        if self.lookup_timeout > MAX_LOOKUP_TIMEOUT {
+            problems.push(format!(
+                "lookup_timeout: must be at most {} seconds",
+                MAX_LOOKUP_TIMEOUT
+            ));
+        }
+
+        if let Some(interface) = self.interface {
+            if interface.is_multicast() || interface.is_broadcast() {
+                problems.push(format!(
+                    "interface: {} is not the address of a network interface",
+                    interface
+                ));
+            }
+
+            // Broadcast discovery uses all interfaces and would silently
+            // ignore it
+            if self.discovery == DiscoveryMode::Broadcast {
+                problems.push(String::from(
+                    "interface: only used with mdns discovery, remove it or set discovery = \"mdns\"",
+                ));
+            }
+        }
+
+        for (index, discovery_key) in self.denylist.iter().enumerate() {
+            let is_valid = discovery_key.len() == DISCOVERY_KEY_LENGTH * 2
+                && discovery_key.chars().all(|c| c.is_ascii_hexdigit());
+
+            if !is_valid {
+                problems.push(format!(
+                    "denylist[{}]: '{}' is not a hex encoded discovery key",
+                    index, discovery_key
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // Returns ~/.config/toy-hypercore/config.toml, respecting XDG_CONFIG_HOME
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(config_dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(config: Config) -> Vec<String> {
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_port_zero() {
+        let config = Config {
+            port: 0,
+            ..Config::default()
+        };
+
+        assert_eq!(problems(config), ["port: must be between 1 and 65535"]);
+    }
+
+    #[test]
+    fn rejects_relay_port_zero() {
+        let config = Config {
+            relay_port: 0,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            ["relay_port: must be between 1 and 65535"]
+        );
+    }
+
+    #[test]
+    fn rejects_broadcast_port_zero() {
+        let config = Config {
+            broadcast_port: 0,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            ["broadcast_port: must be between 1 and 65535"]
+        );
+    }
+
+    #[test]
+    fn rejects_too_long_lookup_timeout() {
+        let config = Config {
+            lookup_timeout: MAX_LOOKUP_TIMEOUT + 1,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            ["lookup_timeout: must be at most 86400 seconds"]
+        );
+    }
+
+    #[test]
+    fn rejects_multicast_interface() {
+        let config = Config {
+            interface: Some(Ipv4Addr::new(224, 0, 0, 251)),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            ["interface: 224.0.0.251 is not the address of a network interface"]
+        );
+    }
+
+    #[test]
+    fn rejects_broadcast_interface() {
+        let config = Config {
+            interface: Some(Ipv4Addr::BROADCAST),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            ["interface: 255.255.255.255 is not the address of a network interface"]
+        );
+    }
+
+    #[test]
+    fn rejects_interface_with_broadcast_discovery() {
+        let config = Config {
+            interface: Some(Ipv4Addr::new(192, 168, 1, 2)),
+            discovery: DiscoveryMode::Broadcast,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            ["interface: only used with mdns discovery, remove it or set discovery = \"mdns\""]
+        );
+    }
+
+    #[test]
+    fn accepts_interface_with_mdns_discovery() {
+        let config = Config {
+            interface: Some(Ipv4Addr::new(192, 168, 1, 2)),
+            ..Config::default()
+        };
+
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_denylist_entry_with_wrong_length() {
+        let config = Config {
+            denylist: vec![String::from("abcd")],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            ["denylist[0]: 'abcd' is not a hex encoded discovery key"]
+        );
+    }
+
+    #[test]
+    fn rejects_denylist_entry_with_non_hex_characters() {
+        let discovery_key = "x".repeat(DISCOVERY_KEY_LENGTH * 2);
+
+        let config = Config {
+            denylist: vec!["a".repeat(DISCOVERY_KEY_LENGTH * 2), discovery_key.clone()],
+            ..Config::default()
+        };
+
+        assert_eq!(
+            problems(config),
+            [format!(
+                "denylist[1]: '{}' is not a hex encoded discovery key",
+                discovery_key
+            )]
+        );
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let config = Config {
+            port: 0,
+            relay_port: 0,
+            denylist: vec![String::from("abcd")],
+            ..Config::default()
+        };
+
+        assert_eq!(problems(config).len(), 3);
+    }
+}
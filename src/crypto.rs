@@ -3,6 +3,11 @@ use ed25519_dalek::Keypair;
 use rand::rngs::OsRng;
 use rand::Rng;
 use sha2::{Digest, Sha256, Sha512};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use crate::error::Error;
 
 const DISCOVERY_KEY_NAME: &[u8] = b"hypercore";
 
@@ -12,6 +17,43 @@ pub fn generate_keypair() -> Keypair {
     Keypair::generate::<Sha512, _>(&mut csprng)
 }
 
+// Generates keypairs on multiple threads until the hex encoded public key
+// starts with the given (lowercase hex) prefix. Every hex character makes
+// this 16 times slower!
+pub fn generate_keypair_with_prefix(prefix: &str, threads: usize) -> Result<Keypair, Error> {
+    let (sender, receiver) = mpsc::channel();
+    let is_found = Arc::new(AtomicBool::new(false));
+
+    for _ in 0..threads {
+        let sender = sender.clone();
+        let is_found = is_found.clone();
+        let prefix = prefix.to_string();
+
+        thread::spawn(move || {
+            let mut csprng: OsRng = OsRng::new().unwrap();
+
+            while !is_found.load(Ordering::Relaxed) {
+                let keypair = Keypair::generate::<Sha512, _>(&mut csprng);
+
+                if hex::encode(keypair.public.as_bytes()).starts_with(&prefix) {
+                    is_found.store(true, Ordering::Relaxed);
+
+                    // Another thread might have been faster, ignore
+                    let _ = sender.send(keypair);
+                }
+            }
+        });
+    }
+
+    // Only the threads hold a sender now, receiving fails instead of blocking
+    // forever when there are none
+    drop(sender);
+
+    receiver
+        .recv()
+        .map_err(|_| Error::InvalidArgument(String::from("no thread left searching for a key")))
+}
+
 pub fn generate_discovery_key(public_key: &[u8]) -> Blake2bResult {
     blake2b(32, public_key, DISCOVERY_KEY_NAME)
 }
@@ -21,3 +63,20 @@ pub fn generate_random_token() -> String {
 
     base64::encode(&Sha256::digest(rnd.as_bytes()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_search_without_threads_fails() {
+        assert!(generate_keypair_with_prefix("", 0).is_err());
+    }
+
+    #[test]
+    fn prefix_search_finds_key() {
+        let keypair = generate_keypair_with_prefix("a", 2).unwrap();
+
+        assert!(hex::encode(keypair.public.as_bytes()).starts_with('a'));
+    }
+}
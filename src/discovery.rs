@@ -1,89 +1,174 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::io::{Cursor, Error};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
 use std::str;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use futures::future;
 use futures::{Future, Stream};
 use tokio::timer::Interval;
-use tokio_core::reactor::Handle;
 use trust_dns::op::{Message, MessageType, Query};
 use trust_dns::rr::{rdata, Name, RData, Record, RecordType};
-use trust_dns_proto::multicast::{MdnsQueryType, MdnsStream};
-use trust_dns_proto::xfer::SerialMessage;
+use trust_dns_proto::xfer::{BufStreamHandle, SerialMessage};
+
+use crate::backend::{DiscoveryBackend, MdnsBackend};
+use crate::chaos::Chaos;
+use crate::clock::{Clock, SystemClock};
+use crate::task::Spawner;
 
 const NAME_SUFFIX: &str = "dat.local";
 
-const MDNS_PORT: u16 = 5353;
-const MDNS_ADDRESS: &str = "224.0.0.251";
+// Fields we read from TXT records of other peers
+const TXT_FIELDS: [&str; 3] = ["token", "peers", "transports"];
+
+// Time-to-live of our answer, others list it as known answer for half of it
+const ANSWER_TTL: u32 = 120;
+
+// Never multicast our answer more often than this (RFC 6762, section 6)
+const ANSWER_INTERVAL: Duration = Duration::from_millis(1000);
+
+// How often we check for peers which outlived the TTL of their answer
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(5000);
+
+// Questions with this bit set in their class ask for a unicast response
+// (RFC 6762, section 5.4)
+const UNICAST_RESPONSE_BIT: u16 = 0x8000;
+
+const HEADER_LENGTH: usize = 12;
 
 pub struct Discovery {
-    handle: Handle,
+    spawner: Spawner,
     name: Name,
     peer: DiscoveryPeer,
+    backend: Rc<dyn DiscoveryBackend>,
+    chaos: Chaos,
+    clock: Rc<dyn Clock>,
 }
 
 impl Discovery {
-    pub fn new(handle: Handle, discovery_key_full: &[u8], port: u16, token: String) -> Discovery {
+    pub fn new(spawner: Spawner, discovery_key_full: &[u8], port: u16, token: String) -> Discovery {
         // Shorten and convert hash to 40 hex chars
         let discovery_key_hex = hex::encode(discovery_key_full);
         let discovery_key = discovery_key_hex[..40].to_string();
 
         // Set DNS name to identify what we are interested in
-        let name = Name::from_ascii(&format!("{}.{}", discovery_key, NAME_SUFFIX)).unwrap();
+        let name = Name::from_ascii(format!("{}.{}", discovery_key, NAME_SUFFIX)).unwrap();
 
         // Define own peer node
         let peer = DiscoveryPeer {
             addr: Ipv4Addr::UNSPECIFIED,
             port,
             token,
+            transports: vec![Transport::new(TransportKind::Tcp, port)],
         };
 
-        Discovery { handle, name, peer }
+        Discovery {
+            spawner,
+            name,
+            peer,
+            backend: Rc::new(MdnsBackend::new(None)),
+            chaos: Chaos::default(),
+            clock: Rc::new(SystemClock),
+        }
+    }
+
+    // Exchange discovery messages through another backend, mDNS is the default
+    pub fn set_backend(&mut self, backend: Rc<dyn DiscoveryBackend>) {
+        self.backend = backend;
+    }
+
+    // Simulate a flaky network by dropping and delaying incoming packets
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = chaos;
+    }
+
+    // Decide about expiry and answer rate limits with another clock
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
     }
 
     pub fn find_peers(
         &self,
-    ) -> impl Future<Item = impl Stream<Item = DiscoveryPeer, Error = Error>, Error = Error> {
-        // Create multicast DNS Stream
-        let multicast_addr = SocketAddr::new(MDNS_ADDRESS.parse().unwrap(), MDNS_PORT);
-
-        let (mdns_stream, mdns_stream_sender) = MdnsStream::new(
-            multicast_addr,
-            MdnsQueryType::OneShotJoin,
-            Some(1),
-            None,
-            None,
-        );
+    ) -> impl Future<Item = impl Stream<Item = DiscoveryEvent, Error = Error>, Error = Error> {
+        // Open the backend stream, messages to the group address reach all
+        // peers of the local network
+        let multicast_addr = self.backend.group_addr();
+        let (mdns_stream, mdns_stream_sender) = self.backend.open(&self.spawner);
 
-        let question_query = self.create_mdns_question().to_vec().unwrap();
+        let question = self.create_mdns_question();
+        let answer_response = self.create_mdns_answer().to_vec().unwrap();
         let mdns_stream_sender_clone = mdns_stream_sender.clone();
 
-        // Send queries to find new peers every 60 seconds
-        let question_interval =
-            Interval::new_interval(Duration::from_millis(60000)).for_each(move |_| {
-                let question_message = SerialMessage::new(question_query.clone(), multicast_addr);
+        let cache = Rc::new(RefCell::new(AnswerCache::new(self.clock.clone())));
+        let cache_clone = cache.clone();
+
+        // Announce ourselves right away with an unsolicited response and ask
+        // peers already on the network to answer us directly, instead of
+        // waiting for the first query interval
+        let mut initial_query = question.to_vec().unwrap();
+        set_unicast_response_bit(&mut initial_query);
+
+        cache.borrow_mut().should_answer();
 
-                mdns_stream_sender_clone
-                    .unbounded_send(question_message)
-                    .unwrap();
+        let announced = vec![answer_response.clone(), initial_query]
+            .into_iter()
+            .try_for_each(|bytes| send_message(&mdns_stream_sender, bytes, multicast_addr));
 
-                Ok(())
-            });
+        // Send queries to find new peers every 60 seconds, they don't produce
+        // events but end the stream when the backend stopped
+        let question_stream = Interval::new_interval(Duration::from_millis(60000))
+            .map_err(Error::other)
+            .and_then(move |_| {
+                let mut question_query = question.clone();
+
+                // List peers we already know so they don't need to answer again
+                for record in cache_clone.borrow().known_answers() {
+                    question_query.add_answer(record);
+                }
 
-        self.handle.spawn(question_interval.then(|_| Ok(())));
+                send_message(
+                    &mdns_stream_sender_clone,
+                    question_query.to_vec().unwrap(),
+                    multicast_addr,
+                )
+            })
+            .filter_map(|_| None);
 
         // Read incoming queries, find interested peers
         // and return them as consumable futures stream
         let name_clone = self.name.clone();
-        let answer_response = self.create_mdns_answer().to_vec().unwrap();
         let token_clone = self.peer.token.clone();
+        let cache_clone = cache.clone();
+        let chaos = self.chaos;
+        let supports_unicast = self.backend.supports_unicast();
+
+        // Check regularly for peers we haven't heard of within their TTL
+        let lost_stream = Interval::new_interval(EXPIRE_INTERVAL)
+            .map_err(Error::other)
+            .map(move |_| {
+                let lost_events: Vec<DiscoveryEvent> = cache_clone
+                    .borrow_mut()
+                    .expire()
+                    .into_iter()
+                    .map(DiscoveryEvent::PeerLost)
+                    .collect();
+
+                futures::stream::iter_ok(lost_events)
+            })
+            .flatten();
+
+        future::result(announced)
+            .and_then(|_| mdns_stream)
+            .and_then(move |stream| {
+                let messages = stream.filter_map(move |message_raw| {
+                    let (mut bytes, source) = message_raw.unwrap();
+                    let is_unicast = take_unicast_response_bit(&mut bytes);
 
-        mdns_stream.and_then(move |stream| {
-            let peer_stream = stream
-                .filter_map(move |message_raw| {
-                    match Message::from_vec(message_raw.bytes()) {
+                    match Message::from_vec(&bytes) {
                         Ok(message) => {
                             // Filter messages looking for same name
                             let has_same_name = message
@@ -92,44 +177,76 @@ impl Discovery {
                                 .any(|q| q.name().eq_case(&name_clone));
 
                             if has_same_name {
-                                Some(message)
+                                Some((message, source, is_unicast))
                             } else {
                                 None
                             }
                         }
                         Err(_) => None,
                     }
-                })
-                .filter_map(move |message| {
-                    match message.message_type() {
-                        MessageType::Query => {
-                            let answer_message =
-                                SerialMessage::new(answer_response.clone(), multicast_addr);
+                });
 
-                            // Respond with answer to query
-                            mdns_stream_sender.unbounded_send(answer_message).unwrap();
+                // Only messages for us go through chaos, unrelated traffic on the
+                // network would hold them up otherwise
+                let event_stream = chaos
+                    .wrap(messages)
+                    .and_then(move |(message, source, is_unicast)| {
+                        match message.message_type() {
+                            MessageType::Query => {
+                                // Known-answer suppression, don't respond when the
+                                // querier still knows about us
+                                let is_known_answer = message.answers().iter().any(|record| {
+                                    record.ttl() >= ANSWER_TTL / 2
+                                        && DiscoveryPeer::from_record(record)
+                                            .is_some_and(|peer| peer.token == token_clone)
+                                });
 
-                            None
-                        }
-                        MessageType::Response => {
-                            // Check if we got response with required fields
-                            match DiscoveryPeer::from_message(&message) {
-                                Some(interested_peer) => {
-                                    // Make sure this is not our response
-                                    if interested_peer.token != token_clone {
-                                        Some(interested_peer)
+                                let answer_addr = cache.borrow_mut().answer_addr(
+                                    source,
+                                    multicast_addr,
+                                    is_unicast,
+                                    supports_unicast,
+                                    is_known_answer,
+                                );
+
+                                if let Some(answer_addr) = answer_addr {
+                                    send_message(
+                                        &mdns_stream_sender,
+                                        answer_response.clone(),
+                                        answer_addr,
+                                    )?;
+                                }
+
+                                Ok(None)
+                            }
+                            MessageType::Response => {
+                                // Check if we got response with required fields
+                                Ok(message.answers().iter().find_map(|record| {
+                                    let mut interested_peer = DiscoveryPeer::from_record(record)?;
+                                    interested_peer.resolve_addr(source.ip());
+
+                                    if interested_peer.token == token_clone {
+                                        // Hearing our own response means it reached the network
+                                        Some(DiscoveryEvent::AnnounceConfirmed)
+                                    } else if cache
+                                        .borrow_mut()
+                                        .insert(interested_peer.clone(), record.clone())
+                                    {
+                                        Some(DiscoveryEvent::PeerFound(interested_peer))
                                     } else {
+                                        // Known peer, only its TTL got refreshed
                                         None
                                     }
-                                }
-                                None => None,
+                                }))
                             }
                         }
-                    }
-                });
+                    })
+                    .filter_map(|event| event)
+                    .select(lost_stream)
+                    .select(question_stream);
 
-            Ok(peer_stream)
-        })
+                Ok(event_stream)
+            })
     }
 
     fn create_mdns_question(&self) -> Message {
@@ -151,11 +268,13 @@ impl Discovery {
         let txt_data = vec![
             format!("token={}", self.peer.token()),
             format!("peers={}", self.peer.encode_peers_field()),
+            format!("transports={}", self.peer.encode_transports_field()),
         ];
 
         let mut record = Record::new();
         record.set_name(self.name.clone());
         record.set_record_type(RecordType::TXT);
+        record.set_ttl(ANSWER_TTL);
         record.set_rdata(RData::TXT(rdata::txt::TXT::new(txt_data)));
 
         message.add_answer(record);
@@ -164,10 +283,275 @@ impl Discovery {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    Utp,
+    Quic,
+    Ws,
+}
+
+impl TransportKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            TransportKind::Tcp => "tcp",
+            TransportKind::Utp => "utp",
+            TransportKind::Quic => "quic",
+            TransportKind::Ws => "ws",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<TransportKind> {
+        match value {
+            "tcp" => Some(TransportKind::Tcp),
+            "utp" => Some(TransportKind::Utp),
+            "quic" => Some(TransportKind::Quic),
+            "ws" => Some(TransportKind::Ws),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transport {
+    kind: TransportKind,
+    port: u16,
+}
+
+impl Transport {
+    pub fn new(kind: TransportKind, port: u16) -> Transport {
+        Transport { kind, port }
+    }
+
+    pub fn kind(&self) -> TransportKind {
+        self.kind
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.kind.as_str(), self.port)
+    }
+}
+
+// Sending only fails when the backend stopped, which ends discovery
+fn send_message(sender: &BufStreamHandle, bytes: Vec<u8>, addr: SocketAddr) -> Result<(), Error> {
+    sender
+        .unbounded_send(SerialMessage::new(bytes, addr))
+        .map_err(|_| Error::other("discovery backend stopped"))
+}
+
+// Returns positions of the class fields of all questions in a raw DNS message
+fn question_class_offsets(bytes: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+
+    if bytes.len() < HEADER_LENGTH {
+        return offsets;
+    }
+
+    let questions_count = BigEndian::read_u16(&bytes[4..6]);
+    let mut offset = HEADER_LENGTH;
+
+    for _ in 0..questions_count {
+        // Skip name, it ends with an empty label or a compression pointer
+        loop {
+            match bytes.get(offset) {
+                Some(0) => {
+                    offset += 1;
+                    break;
+                }
+                Some(length) if length & 0xc0 == 0xc0 => {
+                    offset += 2;
+                    break;
+                }
+                Some(length) => offset += 1 + *length as usize,
+                None => return Vec::new(),
+            }
+        }
+
+        // Skip type, class follows
+        if offset + 4 > bytes.len() {
+            return Vec::new();
+        }
+
+        offsets.push(offset + 2);
+        offset += 4;
+    }
+
+    offsets
+}
+
+// Removes the unicast-response bit from all questions, as trust-dns fails
+// decoding them otherwise, and returns true if any question had it set
+fn take_unicast_response_bit(bytes: &mut [u8]) -> bool {
+    let mut is_unicast = false;
+
+    for offset in question_class_offsets(bytes) {
+        let class = BigEndian::read_u16(&bytes[offset..]);
+
+        if class & UNICAST_RESPONSE_BIT != 0 {
+            BigEndian::write_u16(&mut bytes[offset..], class & !UNICAST_RESPONSE_BIT);
+            is_unicast = true;
+        }
+    }
+
+    is_unicast
+}
+
+fn set_unicast_response_bit(bytes: &mut [u8]) {
+    for offset in question_class_offsets(bytes) {
+        let class = BigEndian::read_u16(&bytes[offset..]);
+        BigEndian::write_u16(&mut bytes[offset..], class | UNICAST_RESPONSE_BIT);
+    }
+}
+
+pub enum DiscoveryEvent {
+    // Another peer interested in the same key showed up
+    PeerFound(DiscoveryPeer),
+    // A peer did not answer within the TTL of its last answer
+    PeerLost(DiscoveryPeer),
+    // Our own answer was heard on the network
+    AnnounceConfirmed,
+}
+
+struct KnownAnswer {
+    peer: DiscoveryPeer,
+    record: Record,
+    received_at: Instant,
+}
+
+impl KnownAnswer {
+    fn is_expired(&self, clock: &dyn Clock) -> bool {
+        clock.elapsed(self.received_at).as_secs() >= u64::from(self.record.ttl())
+    }
+}
+
+// Answers of other peers we've heard recently and when we answered last
+struct AnswerCache {
+    clock: Rc<dyn Clock>,
+    last_answer: Option<Instant>,
+    known_answers: HashMap<PeerId, KnownAnswer>,
+}
+
+impl AnswerCache {
+    fn new(clock: Rc<dyn Clock>) -> AnswerCache {
+        AnswerCache {
+            clock,
+            last_answer: None,
+            known_answers: HashMap::new(),
+        }
+    }
+
+    // Remembers the answer of a peer, returns true if we didn't know it yet
+    fn insert(&mut self, peer: DiscoveryPeer, record: Record) -> bool {
+        let known_answer = KnownAnswer {
+            peer,
+            record,
+            received_at: self.clock.now(),
+        };
+
+        self.known_answers
+            .insert(known_answer.peer.id(), known_answer)
+            .is_none()
+    }
+
+    // Removes and returns peers whose answers outlived their TTL
+    fn expire(&mut self) -> Vec<DiscoveryPeer> {
+        let expired: Vec<PeerId> = self
+            .known_answers
+            .iter()
+            .filter(|(_, known_answer)| known_answer.is_expired(&*self.clock))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        expired
+            .iter()
+            .filter_map(|id| self.known_answers.remove(id))
+            .map(|known_answer| known_answer.peer)
+            .collect()
+    }
+
+    // Returns answers which are younger than half of their TTL, with the TTL
+    // set to what remains of it
+    fn known_answers(&self) -> Vec<Record> {
+        self.known_answers
+            .values()
+            .filter_map(|known_answer| {
+                let ttl = known_answer.record.ttl();
+                let elapsed = self.clock.elapsed(known_answer.received_at).as_secs() as u32;
+
+                if elapsed < ttl / 2 {
+                    let mut record = known_answer.record.clone();
+                    record.set_ttl(ttl - elapsed);
+                    Some(record)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Where to respond with our answer to a query, directly to the querier
+    // when it asked for it. Backends which can't reach it answer everyone
+    // instead, limited like any other answer to everyone. Only answers we
+    // actually send count towards the rate limit
+    fn answer_addr(
+        &mut self,
+        source: SocketAddr,
+        multicast_addr: SocketAddr,
+        is_unicast: bool,
+        supports_unicast: bool,
+        is_known_answer: bool,
+    ) -> Option<SocketAddr> {
+        if is_known_answer {
+            None
+        } else if is_unicast && supports_unicast {
+            Some(source)
+        } else if self.should_answer() {
+            Some(multicast_addr)
+        } else {
+            None
+        }
+    }
+
+    // Returns false when we've just answered, otherwise remembers this answer
+    fn should_answer(&mut self) -> bool {
+        match self.last_answer {
+            Some(last_answer) if self.clock.elapsed(last_answer) < ANSWER_INTERVAL => false,
+            _ => {
+                self.last_answer = Some(self.clock.now());
+                true
+            }
+        }
+    }
+}
+
+// Identifies a peer, the token alone is not enough as it is chosen by the
+// peer itself, the same address can be shared by peers behind a NAT and the
+// same address and port can be reused by a restarted peer
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PeerId {
+    token: String,
+    addr: Ipv4Addr,
+    port: u16,
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.token, self.addr, self.port)
+    }
+}
+
+#[derive(Clone)]
 pub struct DiscoveryPeer {
     addr: Ipv4Addr,
     port: u16,
     token: String,
+    transports: Vec<Transport>,
 }
 
 impl DiscoveryPeer {
@@ -183,45 +567,92 @@ impl DiscoveryPeer {
         self.token.clone()
     }
 
-    fn from_message(message: &Message) -> Option<DiscoveryPeer> {
-        // Check TXT records of message for needed fields
-        message.answers().iter().find_map(|rr| {
-            if let RData::TXT(ref rdata) = *rr.rdata() {
-                // Append only "token" and "peers" fields
-                let fields: Vec<Vec<&str>> = rdata
-                    .iter()
-                    .map(|d| str::from_utf8(d).unwrap())
-                    .map(|s| s.splitn(2, '=').collect())
-                    .filter_map(|t: Vec<&str>| {
-                        if t.len() == 2 && (t[0] == "token" || t[0] == "peers") {
-                            Some(t)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
+    pub fn id(&self) -> PeerId {
+        PeerId {
+            token: self.token.clone(),
+            addr: self.addr,
+            port: self.port,
+        }
+    }
+
+    pub fn transports(&self) -> &[Transport] {
+        &self.transports
+    }
+
+    // Pick the first transport of our preference list the peer supports as well
+    pub fn select_transport(&self, preferred: &[TransportKind]) -> Option<Transport> {
+        preferred.iter().find_map(|kind| {
+            self.transports
+                .iter()
+                .find(|transport| transport.kind() == *kind)
+                .cloned()
+        })
+    }
 
-                // Both "token" and "peers" should be given
-                if fields.len() == 2 {
-                    let mut map: HashMap<String, String> = HashMap::with_capacity(2);
+    // Returns None for records which are no valid announcement, anyone on the
+    // network can send them
+    fn from_record(rr: &Record) -> Option<DiscoveryPeer> {
+        // Check TXT record for needed fields
+        if let RData::TXT(ref rdata) = *rr.rdata() {
+            let strings = rdata
+                .iter()
+                .map(|d| str::from_utf8(d).ok())
+                .collect::<Option<Vec<&str>>>()?;
 
-                    for field in fields {
-                        map.insert(String::from(field[0]), String::from(field[1]));
+            // Append only "token", "peers" and "transports" fields
+            let fields: Vec<Vec<&str>> = strings
+                .into_iter()
+                .map(|s| s.splitn(2, '=').collect())
+                .filter_map(|t: Vec<&str>| {
+                    if t.len() == 2 && TXT_FIELDS.contains(&t[0]) {
+                        Some(t)
+                    } else {
+                        None
                     }
+                })
+                .collect();
 
-                    let token = map["token"].clone();
-                    let peers = map["peers"].clone();
+            let mut map: HashMap<String, String> = HashMap::with_capacity(TXT_FIELDS.len());
 
-                    let (addr, port) = DiscoveryPeer::decode_peers_field(&peers);
+            for field in fields {
+                map.insert(String::from(field[0]), String::from(field[1]));
+            }
 
-                    Some(DiscoveryPeer { port, addr, token })
-                } else {
-                    None
-                }
+            // Both "token" and "peers" should be given
+            if map.contains_key("token") && map.contains_key("peers") {
+                let token = map["token"].clone();
+                let peers = map["peers"].clone();
+
+                let (addr, port) = DiscoveryPeer::decode_peers_field(&peers)?;
+
+                // Peers not advertising their transports only speak TCP
+                let transports = match map.get("transports") {
+                    Some(transports) => DiscoveryPeer::decode_transports_field(transports),
+                    None => vec![Transport::new(TransportKind::Tcp, port)],
+                };
+
+                Some(DiscoveryPeer {
+                    port,
+                    addr,
+                    token,
+                    transports,
+                })
             } else {
                 None
             }
-        })
+        } else {
+            None
+        }
+    }
+
+    // Peers announce the unspecified address, use the one we received the
+    // announcement from instead
+    fn resolve_addr(&mut self, source: IpAddr) {
+        if let IpAddr::V4(source) = source {
+            if self.addr.is_unspecified() {
+                self.addr = source;
+            }
+        }
     }
 
     fn encode_peers_field(&self) -> String {
@@ -236,18 +667,481 @@ impl DiscoveryPeer {
         base64::encode(&writer)
     }
 
-    fn decode_peers_field(data: &str) -> (Ipv4Addr, u16) {
-        let mut reader = Cursor::new(base64::decode(data).unwrap());
+    fn decode_peers_field(data: &str) -> Option<(Ipv4Addr, u16)> {
+        let bytes = base64::decode(data).ok()?;
+
+        // Four bytes address followed by two bytes port
+        if bytes.len() != 6 {
+            return None;
+        }
+
+        let mut reader = Cursor::new(bytes);
 
         let addr = Ipv4Addr::new(
-            reader.read_u8().unwrap(),
-            reader.read_u8().unwrap(),
-            reader.read_u8().unwrap(),
-            reader.read_u8().unwrap(),
+            reader.read_u8().ok()?,
+            reader.read_u8().ok()?,
+            reader.read_u8().ok()?,
+            reader.read_u8().ok()?,
+        );
+
+        let port = reader.read_u16::<BigEndian>().ok()?;
+
+        Some((addr, port))
+    }
+
+    fn encode_transports_field(&self) -> String {
+        self.transports
+            .iter()
+            .map(|transport| transport.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    fn decode_transports_field(data: &str) -> Vec<Transport> {
+        // Ignore malformed entries and transports we don't know about
+        data.split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.splitn(2, ':');
+                let kind = TransportKind::from_str(parts.next()?)?;
+                let port = parts.next()?.parse().ok()?;
+
+                Some(Transport::new(kind, port))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::MockClock;
+
+    fn txt_record(strings: &[&str]) -> Record {
+        let txt_data = strings.iter().map(|string| string.to_string()).collect();
+
+        let mut record = Record::new();
+        record.set_record_type(RecordType::TXT);
+        record.set_rdata(RData::TXT(rdata::txt::TXT::new(txt_data)));
+        record
+    }
+
+    fn peer(token: &str, addr: [u8; 4], port: u16) -> DiscoveryPeer {
+        DiscoveryPeer {
+            addr: Ipv4Addr::from(addr),
+            port,
+            token: token.to_string(),
+            transports: vec![Transport::new(TransportKind::Tcp, port)],
+        }
+    }
+
+    fn question_bytes() -> Vec<u8> {
+        let mut message = Message::new();
+        let mut query = Query::new();
+        query.set_query_type(RecordType::TXT);
+        query.set_name(Name::from_ascii("abc.dat.local").unwrap());
+        message.add_query(query);
+
+        message.to_vec().unwrap()
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(192, 168, 1, 2).into(), port)
+    }
+
+    fn answer_record(ttl: u32) -> Record {
+        let mut record = txt_record(&["token=abc", "peers=wKgBAjA5"]);
+        record.set_ttl(ttl);
+        record
+    }
+
+    #[test]
+    fn from_record_reads_valid_announcement() {
+        let peer = DiscoveryPeer::from_record(&txt_record(&[
+            "token=abc",
+            "peers=wKgBAjA5",
+            "transports=tcp:12345",
+        ]))
+        .unwrap();
+
+        assert_eq!(peer.token(), "abc");
+        assert_eq!(peer.addr(), Ipv4Addr::new(192, 168, 1, 2));
+        assert_eq!(peer.port(), 12345);
+        assert_eq!(
+            peer.transports(),
+            [Transport::new(TransportKind::Tcp, 12345)]
+        );
+    }
+
+    #[test]
+    fn from_record_falls_back_to_tcp_without_transports() {
+        let peer =
+            DiscoveryPeer::from_record(&txt_record(&["token=abc", "peers=wKgBAjA5"])).unwrap();
+
+        assert_eq!(
+            peer.transports(),
+            [Transport::new(TransportKind::Tcp, 12345)]
+        );
+    }
+
+    #[test]
+    fn decodes_transports_field() {
+        assert_eq!(
+            DiscoveryPeer::decode_transports_field("tcp:1,quic:2"),
+            [
+                Transport::new(TransportKind::Tcp, 1),
+                Transport::new(TransportKind::Quic, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unknown_and_malformed_transports() {
+        assert_eq!(
+            DiscoveryPeer::decode_transports_field("carrier-pigeon:1,tcp,utp:x,ws:70000,ws:3,"),
+            [Transport::new(TransportKind::Ws, 3)]
+        );
+    }
+
+    #[test]
+    fn selects_transport_by_our_preference() {
+        let mut peer = peer("a", [192, 168, 1, 2], 12345);
+        peer.transports = vec![
+            Transport::new(TransportKind::Tcp, 1),
+            Transport::new(TransportKind::Quic, 2),
+        ];
+
+        assert_eq!(
+            peer.select_transport(&[TransportKind::Quic, TransportKind::Tcp]),
+            Some(Transport::new(TransportKind::Quic, 2))
+        );
+        assert_eq!(
+            peer.select_transport(&[TransportKind::Utp, TransportKind::Tcp]),
+            Some(Transport::new(TransportKind::Tcp, 1))
+        );
+        assert_eq!(peer.select_transport(&[TransportKind::Ws]), None);
+    }
+
+    #[test]
+    fn from_record_rejects_invalid_base64_peers() {
+        assert!(DiscoveryPeer::from_record(&txt_record(&["token=abc", "peers=!!"])).is_none());
+    }
+
+    #[test]
+    fn from_record_rejects_short_peers() {
+        // Only three bytes
+        assert!(DiscoveryPeer::from_record(&txt_record(&["token=abc", "peers=wKgB"])).is_none());
+    }
+
+    #[test]
+    fn from_record_rejects_long_peers() {
+        // Eight bytes
+        let record = txt_record(&["token=abc", "peers=wKgBAjA5AAA="]);
+
+        assert!(DiscoveryPeer::from_record(&record).is_none());
+    }
+
+    #[test]
+    fn from_record_rejects_non_utf8() {
+        // TXT::new only takes valid strings, break the token in the encoded
+        // message instead
+        let mut message = Message::new();
+        message.add_answer(txt_record(&["token=ab", "peers=wKgBAjA5"]));
+
+        let mut bytes = message.to_vec().unwrap();
+        let token_offset = bytes.windows(8).position(|w| w == b"token=ab").unwrap();
+        bytes[token_offset + 6] = 0xff;
+        bytes[token_offset + 7] = 0xfe;
+
+        let message = Message::from_vec(&bytes).unwrap();
+
+        assert!(DiscoveryPeer::from_record(&message.answers()[0]).is_none());
+    }
+
+    #[test]
+    fn from_record_rejects_missing_fields() {
+        assert!(DiscoveryPeer::from_record(&txt_record(&["token=abc"])).is_none());
+        assert!(DiscoveryPeer::from_record(&txt_record(&["peers=wKgBAjA5"])).is_none());
+    }
+
+    #[test]
+    fn different_tokens_at_same_address_stay_apart() {
+        let mut cache = AnswerCache::new(Rc::new(SystemClock));
+
+        assert!(cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL)
+        ));
+        assert!(cache.insert(
+            peer("b", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL)
+        ));
+        assert_eq!(cache.known_answers.len(), 2);
+    }
+
+    #[test]
+    fn same_token_at_different_addresses_stays_apart() {
+        let mut cache = AnswerCache::new(Rc::new(SystemClock));
+
+        assert!(cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL)
+        ));
+        assert!(cache.insert(
+            peer("a", [192, 168, 1, 3], 12345),
+            answer_record(ANSWER_TTL)
+        ));
+        assert!(cache.insert(
+            peer("a", [192, 168, 1, 3], 23456),
+            answer_record(ANSWER_TTL)
+        ));
+        assert_eq!(cache.known_answers.len(), 3);
+    }
+
+    #[test]
+    fn known_peer_is_not_reported_again() {
+        let mut cache = AnswerCache::new(Rc::new(SystemClock));
+
+        assert!(cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL)
+        ));
+        assert!(!cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL)
+        ));
+    }
+
+    #[test]
+    fn restarted_peer_replaces_its_old_token() {
+        let clock = MockClock::new();
+        let mut cache = AnswerCache::new(Rc::new(clock.clone()));
+
+        cache.insert(
+            peer("before", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL),
+        );
+        clock.advance(Duration::from_secs(100));
+        cache.insert(
+            peer("after", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL),
+        );
+
+        // Only the old token is lost, the restarted peer stays known
+        clock.advance(Duration::from_secs(u64::from(ANSWER_TTL) - 100));
+        let expired = cache.expire();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].token(), "before");
+
+        assert!(!cache.insert(
+            peer("after", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL)
+        ));
+    }
+
+    #[test]
+    fn peer_expires_after_its_ttl() {
+        let clock = MockClock::new();
+        let mut cache = AnswerCache::new(Rc::new(clock.clone()));
+
+        cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL),
+        );
+
+        clock.advance(Duration::from_secs(u64::from(ANSWER_TTL) - 1));
+        assert!(cache.expire().is_empty());
+
+        clock.advance(Duration::from_secs(1));
+        let expired = cache.expire();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].token(), "a");
+        assert!(cache.known_answers.is_empty());
+    }
+
+    #[test]
+    fn refreshed_peer_does_not_expire() {
+        let clock = MockClock::new();
+        let mut cache = AnswerCache::new(Rc::new(clock.clone()));
+
+        cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL),
+        );
+        clock.advance(Duration::from_secs(100));
+        cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL),
+        );
+        clock.advance(Duration::from_secs(100));
+
+        assert!(cache.expire().is_empty());
+    }
+
+    #[test]
+    fn known_answers_age_until_half_of_ttl() {
+        let clock = MockClock::new();
+        let mut cache = AnswerCache::new(Rc::new(clock.clone()));
+
+        cache.insert(
+            peer("a", [192, 168, 1, 2], 12345),
+            answer_record(ANSWER_TTL),
+        );
+        assert_eq!(cache.known_answers()[0].ttl(), ANSWER_TTL);
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(cache.known_answers()[0].ttl(), ANSWER_TTL - 30);
+
+        clock.advance(Duration::from_secs(u64::from(ANSWER_TTL / 2) - 30));
+        assert!(cache.known_answers().is_empty());
+    }
+
+    #[test]
+    fn answers_are_rate_limited() {
+        let clock = MockClock::new();
+        let mut cache = AnswerCache::new(Rc::new(clock.clone()));
+
+        assert!(cache.should_answer());
+        assert!(!cache.should_answer());
+
+        clock.advance(ANSWER_INTERVAL - Duration::from_millis(1));
+        assert!(!cache.should_answer());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(cache.should_answer());
+        assert!(!cache.should_answer());
+    }
+
+    #[test]
+    fn suppressed_query_does_not_use_up_rate_limit() {
+        let mut cache = AnswerCache::new(Rc::new(MockClock::new()));
+        let multicast_addr = addr(5353);
+
+        // Querier still knows us, we stay quiet
+        assert_eq!(
+            cache.answer_addr(addr(1), multicast_addr, false, true, true),
+            None
+        );
+
+        // Next querier doesn't know us and gets an answer right away
+        assert_eq!(
+            cache.answer_addr(addr(2), multicast_addr, false, true, false),
+            Some(multicast_addr)
+        );
+        assert_eq!(
+            cache.answer_addr(addr(3), multicast_addr, false, true, false),
+            None
+        );
+    }
+
+    #[test]
+    fn unicast_response_bit_round_trip() {
+        let mut bytes = question_bytes();
+        let class_offset = bytes.len() - 2;
+
+        set_unicast_response_bit(&mut bytes);
+        assert_eq!(bytes[class_offset..], [0x80, 0x01]);
+
+        assert!(take_unicast_response_bit(&mut bytes));
+        assert!(!take_unicast_response_bit(&mut bytes));
+        assert_eq!(bytes, question_bytes());
+
+        let message = Message::from_vec(&bytes).unwrap();
+        assert_eq!(
+            message.queries()[0].name(),
+            &Name::from_ascii("abc.dat.local").unwrap()
+        );
+    }
+
+    #[test]
+    fn ignores_truncated_header() {
+        let mut bytes = question_bytes()[..HEADER_LENGTH - 1].to_vec();
+
+        assert!(question_class_offsets(&bytes).is_empty());
+        assert!(!take_unicast_response_bit(&mut bytes));
+    }
+
+    #[test]
+    fn ignores_truncated_question() {
+        let mut question = question_bytes();
+        set_unicast_response_bit(&mut question);
+
+        // Cut off in the middle of the class and in the middle of the name
+        for length in [question.len() - 1, HEADER_LENGTH + 2] {
+            let mut bytes = question[..length].to_vec();
+
+            assert!(question_class_offsets(&bytes).is_empty());
+            assert!(!take_unicast_response_bit(&mut bytes));
+            assert_eq!(bytes, question[..length]);
+        }
+    }
+
+    #[test]
+    fn follows_compression_pointer_in_name() {
+        let mut bytes = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+
+        // "abc.local", TXT, unicast response
+        bytes.extend_from_slice(b"\x03abc\x05local\x00\x00\x10\x80\x01");
+        // Pointer to the first name, TXT, unicast response
+        bytes.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x10, 0x80, 0x01]);
+
+        assert_eq!(question_class_offsets(&bytes), [25, 31]);
+        assert!(take_unicast_response_bit(&mut bytes));
+
+        let message = Message::from_vec(&bytes).unwrap();
+        let name = Name::from_ascii("abc.local").unwrap();
+
+        assert_eq!(message.queries().len(), 2);
+        assert!(message.queries().iter().all(|query| query.name() == &name));
+    }
+
+    #[test]
+    fn ignores_message_without_questions() {
+        let mut bytes = Message::new().to_vec().unwrap();
+        let original = bytes.clone();
+
+        assert!(question_class_offsets(&bytes).is_empty());
+
+        set_unicast_response_bit(&mut bytes);
+        assert!(!take_unicast_response_bit(&mut bytes));
+        assert_eq!(bytes, original);
+    }
+
+    #[test]
+    fn unicast_query_is_answered_directly_without_rate_limit() {
+        let mut cache = AnswerCache::new(Rc::new(MockClock::new()));
+        let multicast_addr = addr(5353);
+
+        assert!(cache.should_answer());
+        assert_eq!(
+            cache.answer_addr(addr(1), multicast_addr, true, true, false),
+            Some(addr(1))
         );
+        assert_eq!(
+            cache.answer_addr(addr(2), multicast_addr, true, true, false),
+            Some(addr(2))
+        );
+    }
 
-        let port = reader.read_u16::<BigEndian>().unwrap();
+    #[test]
+    fn unicast_query_without_unicast_support_is_rate_limited() {
+        let clock = MockClock::new();
+        let mut cache = AnswerCache::new(Rc::new(clock.clone()));
+        let multicast_addr = addr(5354);
 
-        (addr, port)
+        assert_eq!(
+            cache.answer_addr(addr(1), multicast_addr, true, false, false),
+            Some(multicast_addr)
+        );
+        assert_eq!(
+            cache.answer_addr(addr(2), multicast_addr, true, false, false),
+            None
+        );
+
+        clock.advance(ANSWER_INTERVAL);
+        assert_eq!(
+            cache.answer_addr(addr(2), multicast_addr, true, false, false),
+            Some(multicast_addr)
+        );
     }
 }
@@ -0,0 +1,84 @@
+use std::net::{Ipv4Addr, TcpListener};
+
+use crate::backend::{BroadcastBackend, DiscoveryBackend, MdnsBackend};
+use crate::config::{Config, DiscoveryMode};
+use crate::error::Error;
+
+// Runs all checks against the given configuration and prints what failed
+// together with a hint how to fix it. Only checks of what is configured count
+// as failed, others are reported as warnings. The relay port only counts when
+// we're checking for running a relay
+pub fn run(config: &Config, is_relay: bool) -> Result<(), Error> {
+    let is_mdns = config.discovery == DiscoveryMode::Mdns;
+
+    let checks = [
+        ("Configuration", check_config(config), true),
+        ("Multicast discovery", check_multicast(config), is_mdns),
+        ("Broadcast discovery", check_broadcast(config), !is_mdns),
+        ("Port", check_tcp_port(config.port, "--port"), true),
+        (
+            "Relay port",
+            check_tcp_port(config.relay_port, "relay -p"),
+            is_relay,
+        ),
+    ];
+
+    let mut failed = 0;
+
+    for (name, result, is_required) in checks.iter() {
+        match result {
+            Ok(()) => println!("[ok] {}", name),
+            Err(hint) if *is_required => {
+                println!("[failed] {}: {}", name, hint);
+                failed += 1;
+            }
+            Err(hint) => println!("[warning] {}: {}", name, hint),
+        }
+    }
+
+    if failed > 0 {
+        Err(Error::ChecksFailed(failed))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_config(config: &Config) -> Result<(), String> {
+    config.validate().map_err(|problems| problems.join("; "))
+}
+
+// Joins the group the same way the mDNS backend does
+fn check_multicast(config: &Config) -> Result<(), String> {
+    let backend = MdnsBackend::new(config.interface);
+    let group_addr = backend.group_addr();
+
+    backend.bind().map(|_| ()).map_err(|err| {
+        format!(
+            "could not join {} ({}), pick another interface with -i or use --discovery broadcast",
+            group_addr, err
+        )
+    })
+}
+
+fn check_broadcast(config: &Config) -> Result<(), String> {
+    BroadcastBackend::new(config.broadcast_port)
+        .bind()
+        .map(|_| ())
+        .map_err(|err| {
+            format!(
+                "could not listen on UDP port {} ({}), choose another one with --broadcast-port",
+                config.broadcast_port, err
+            )
+        })
+}
+
+fn check_tcp_port(port: u16, option: &str) -> Result<(), String> {
+    TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+        .map(|_| ())
+        .map_err(|err| {
+            format!(
+                "could not listen on TCP port {} ({}), choose another one with {}",
+                port, err, option
+            )
+        })
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+// Exit codes follow the BSD sysexits.h conventions
+const EXIT_USAGE: i32 = 64;
+const EXIT_DATA: i32 = 65;
+const EXIT_NOHOST: i32 = 68;
+const EXIT_UNAVAILABLE: i32 = 69;
+const EXIT_NOPERM: i32 = 77;
+const EXIT_CONFIG: i32 = 78;
+
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),
+    InvalidArgument(String),
+    InvalidKey(String),
+    DeniedKey(String),
+    Config(io::Error),
+    InvalidConfig(Vec<String>),
+    Network(io::Error),
+    NoPeersFound(Duration),
+    ChecksFailed(usize),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Usage(_) | Error::InvalidArgument(_) => EXIT_USAGE,
+            Error::InvalidKey(_) => EXIT_DATA,
+            Error::DeniedKey(_) => EXIT_NOPERM,
+            Error::Config(_) | Error::InvalidConfig(_) => EXIT_CONFIG,
+            Error::Network(_) | Error::ChecksFailed(_) => EXIT_UNAVAILABLE,
+            Error::NoPeersFound(_) => EXIT_NOHOST,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Usage(ref message) => write!(f, "{}", message),
+            Error::InvalidArgument(ref message) => write!(f, "Invalid argument: {}", message),
+            Error::InvalidKey(ref message) => write!(f, "Invalid key: {}", message),
+            Error::DeniedKey(ref discovery_key) => {
+                write!(f, "Discovery key {} is on the denylist", discovery_key)
+            }
+            Error::Config(ref err) => write!(f, "Could not load configuration: {}", err),
+            Error::InvalidConfig(ref problems) => {
+                write!(f, "Invalid configuration:")?;
+
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+
+                Ok(())
+            }
+            Error::Network(ref err) => write!(f, "Network error: {}", err),
+            Error::NoPeersFound(ref timeout) => {
+                write!(f, "No peers found within {} seconds", timeout.as_secs())
+            }
+            Error::ChecksFailed(count) => write!(f, "{} checks failed", count),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
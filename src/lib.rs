@@ -0,0 +1,27 @@
+extern crate base64;
+extern crate blake2_rfc;
+extern crate byteorder;
+extern crate ed25519_dalek;
+extern crate futures;
+extern crate hex;
+extern crate net2;
+extern crate rand;
+extern crate serde;
+extern crate sha2;
+extern crate tokio;
+extern crate tokio_core;
+extern crate toml;
+extern crate trust_dns;
+extern crate trust_dns_proto;
+
+pub mod backend;
+pub mod chaos;
+pub mod clock;
+pub mod config;
+pub mod crypto;
+pub mod discovery;
+pub mod doctor;
+pub mod error;
+pub mod node;
+pub mod relay;
+pub mod task;
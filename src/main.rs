@@ -1,115 +1,465 @@
-extern crate base64;
-extern crate blake2_rfc;
-extern crate byteorder;
+#[macro_use]
+extern crate clap;
 extern crate ed25519_dalek;
 extern crate futures;
-extern crate getopts;
 extern crate hex;
-extern crate rand;
-extern crate sha2;
 extern crate tokio;
-extern crate tokio_core;
-extern crate trust_dns;
-extern crate trust_dns_proto;
+extern crate toy_hypercore;
 
-pub mod crypto;
-pub mod discovery;
-
-use discovery::{Discovery, DiscoveryPeer};
-use futures::{Async, Future, Stream};
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, Shell, SubCommand};
+use ed25519_dalek::PUBLIC_KEY_LENGTH;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use toy_hypercore::backend::{BroadcastBackend, DiscoveryBackend, MdnsBackend};
+use toy_hypercore::chaos::Chaos;
+use toy_hypercore::config::{Config, DiscoveryMode, MAX_LOOKUP_TIMEOUT};
+use toy_hypercore::crypto;
+use toy_hypercore::discovery::{DiscoveryEvent, DiscoveryPeer, PeerId, TransportKind};
+use toy_hypercore::doctor;
+use toy_hypercore::error::Error;
+use toy_hypercore::node::{Node, NodeBuilder, NodeEvent};
+use toy_hypercore::relay::Relay;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
 
-use tokio_core::reactor::{Core, Handle};
+use tokio::timer::Interval;
+use tokio_core::reactor::Core;
 
 const DAT_URL_PROTOCOL: &str = "dat://";
 
+// How often we check if cloning should give up looking for peers
+const LOOKUP_CHECK_INTERVAL: Duration = Duration::from_millis(1000);
+
+// Transports we can dial, in order of preference
+const SUPPORTED_TRANSPORTS: [TransportKind; 1] = [TransportKind::Tcp];
+
 fn run(
-    handle: Handle,
-    discovery_key_full: &[u8],
-    token: String,
-) -> impl Future<Item = (), Error = ()> {
-    let mut peers: HashMap<String, DiscoveryPeer> = HashMap::new();
-
-    // @TODO Get correct port from listening TCP socket
-    let port = 12345;
-
-    // Discover interesting peers
-    let discovery = Discovery::new(handle.clone(), discovery_key_full, port, token);
-
-    let handle_clone = handle.clone();
-
-    let discovery_stream = discovery.find_peers().then(move |peer_stream| {
-        let find_peers = peer_stream.unwrap().for_each(move |peer| {
-            if !peers.contains_key(&peer.token()) {
-                println!(
-                    "New peer: {}, {}, {}",
-                    peer.addr(),
-                    peer.port(),
-                    peer.token()
-                );
-
-                peers.insert(peer.token(), peer);
-            }
+    node: &mut Node,
+    config: &Config,
+    is_cloning: bool,
+) -> impl Future<Item = (), Error = Error> {
+    let mut peers: HashMap<PeerId, DiscoveryPeer> = HashMap::new();
+    let mut is_announced = false;
 
-            Ok(())
-        });
+    let lookup = Rc::new(RefCell::new(
+        node.lookup(Duration::from_secs(config.lookup_timeout)),
+    ));
+    let lookup_clone = lookup.clone();
 
-        handle_clone.spawn(find_peers.then(|_| Ok(())));
+    // Keep on finding peers until discovery fails
+    let events = node.events().expect("Node events are only taken here");
+
+    let find_peers = events.for_each(move |NodeEvent { event, .. }| {
+        match event {
+            DiscoveryEvent::PeerFound(peer) => match peer.select_transport(&SUPPORTED_TRANSPORTS) {
+                Some(transport) => {
+                    println!("New peer: {}, {}", peer.id(), transport);
+
+                    peers.insert(peer.id(), peer);
+                    lookup_clone.borrow_mut().peer_found();
+                }
+                None => {
+                    let transports: Vec<String> =
+                        peer.transports().iter().map(|t| t.to_string()).collect();
+
+                    println!(
+                        "Ignoring peer without supported transport: {}, {}",
+                        peer.id(),
+                        transports.join(",")
+                    );
+                }
+            },
+            DiscoveryEvent::PeerLost(peer) => {
+                if peers.remove(&peer.id()).is_some() {
+                    println!("Lost peer: {}", peer.id());
+                }
+            }
+            DiscoveryEvent::AnnounceConfirmed => {
+                if !is_announced {
+                    println!("Announced on local network");
+                    is_announced = true;
+                }
+            }
+        }
 
         Ok(())
     });
 
-    handle.spawn(discovery_stream);
+    // Give up cloning when nobody showed up in time
+    let no_peers_found = if is_cloning && config.lookup_timeout > 0 {
+        Either::A(
+            Interval::new_interval(LOOKUP_CHECK_INTERVAL)
+                .map_err(|err| Error::Network(io::Error::other(err)))
+                .for_each(move |_| lookup.borrow().check()),
+        )
+    } else {
+        Either::B(future::empty())
+    };
 
-    // Never end this future
-    futures::future::poll_fn(|| Ok(Async::NotReady))
+    find_peers
+        .select(no_peers_found)
+        .map(|(item, _)| item)
+        .map_err(|(err, _)| err)
 }
 
-fn main() {
-    // Parse command-line arguments
-    let args: Vec<String> = std::env::args().collect();
+fn cli() -> App<'static, 'static> {
+    let port = Arg::with_name("port")
+        .short("p")
+        .long("port")
+        .value_name("port")
+        .help("Port to listen on")
+        .takes_value(true);
 
-    let mut opts = getopts::Options::new();
-    opts.optopt("c", "clone", "clone data from this URL", "<link>");
+    App::new("toy-hypercore")
+        .version(crate_version!())
+        .about("Toy Hypercore p2p protocol implementation")
+        .arg(
+            Arg::with_name("clone")
+                .short("c")
+                .long("clone")
+                .value_name("link")
+                .help("Clone data from this URL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interface")
+                .short("i")
+                .long("interface")
+                .value_name("address")
+                .help("Address of the network interface used for discovery")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("discovery")
+                .long("discovery")
+                .value_name("backend")
+                .help("How to find peers on the local network")
+                .takes_value(true)
+                .possible_values(&["mdns", "broadcast"]),
+        )
+        .arg(
+            Arg::with_name("broadcast-port")
+                .long("broadcast-port")
+                .value_name("port")
+                .help("Port used for broadcast discovery")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lookup-timeout")
+                .long("lookup-timeout")
+                .value_name("seconds")
+                .help("Seconds to wait for peers when cloning, 0 waits forever")
+                .takes_value(true)
+                .requires("clone"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("path")
+                .help("Load configuration from this file")
+                .takes_value(true),
+        )
+        .arg(port.clone())
+        .arg(
+            Arg::with_name("chaos-latency")
+                .long("chaos-latency")
+                .value_name("milliseconds")
+                .help("Delays every incoming discovery packet")
+                .takes_value(true)
+                .hidden(true),
+        )
+        .arg(
+            Arg::with_name("chaos-drop")
+                .long("chaos-drop")
+                .value_name("percent")
+                .help("Drops this share of incoming discovery packets")
+                .takes_value(true)
+                .hidden(true),
+        )
+        .subcommand(
+            SubCommand::with_name("relay")
+                .about("Pipes together peers meeting at the same rendezvous token")
+                .arg(port),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Generates a new keypair")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("hex")
+                        .help("Search for a public key starting with these hex characters")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .value_name("count")
+                        .help("Number of threads searching for a key, defaults to CPU count")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("key")
+                .about("Works with keys")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("inspect")
+                        .about("Prints details about a public key")
+                        .arg(
+                            Arg::with_name("key")
+                                .required(true)
+                                .help("dat:// URL or hex encoded public key"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Checks if this host is ready to find and serve peers")
+                .arg(
+                    Arg::with_name("relay")
+                        .long("relay")
+                        .help("Fails when the relay port is not available as well"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Generates shell completions")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        )
+}
 
-    // Generate public and secret keypair
-    let keypair = crypto::generate_keypair();
+fn parse_port(matches: &ArgMatches) -> Result<Option<u16>, Error> {
+    match matches.value_of("port") {
+        Some(port) => port
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidArgument(format!("'{}' is not a valid port", port))),
+        None => Ok(None),
+    }
+}
 
-    // Create or clone hypercore depending on given arguments
-    let matches = opts.parse(&args[1..]).unwrap();
-    let is_cloning = matches.opt_present("clone");
+fn parse_dat_url(url: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(url.replace(DAT_URL_PROTOCOL, ""))
+        .ok()
+        .filter(|key| key.len() == PUBLIC_KEY_LENGTH)
+        .ok_or_else(|| Error::InvalidKey(format!("'{}' is not a valid dat URL", url)))
+}
 
-    // Prepare dat:// URL with public key
-    let decoded_key;
+fn parse_chaos(matches: &ArgMatches) -> Result<Chaos, Error> {
+    let latency = match matches.value_of("chaos-latency") {
+        Some(latency) => latency
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("'{}' is not a valid latency", latency)))?,
+        None => 0,
+    };
 
-    let public_key: &[u8] = if is_cloning {
-        let clone_public_key = matches
-            .opt_str("clone")
-            .unwrap()
-            .replace(DAT_URL_PROTOCOL, "");
+    let drop_percent: f64 = match matches.value_of("chaos-drop") {
+        Some(drop) => drop
+            .parse()
+            .ok()
+            .filter(|drop| (0.0..=100.0).contains(drop))
+            .ok_or_else(|| Error::InvalidArgument(format!("'{}' is not a percentage", drop)))?,
+        None => 0.0,
+    };
 
-        decoded_key = hex::decode(clone_public_key).unwrap();
-        &decoded_key
-    } else {
-        keypair.public.as_bytes()
+    Ok(Chaos::new(
+        Duration::from_millis(latency),
+        drop_percent / 100.0,
+    ))
+}
+
+fn keygen(matches: &ArgMatches) -> Result<(), Error> {
+    let keypair = match matches.value_of("prefix") {
+        Some(prefix) => {
+            let prefix = prefix.to_lowercase();
+
+            let is_valid_prefix = prefix.len() <= PUBLIC_KEY_LENGTH * 2
+                && prefix.chars().all(|c| c.is_ascii_hexdigit());
+
+            if !is_valid_prefix {
+                return Err(Error::InvalidArgument(format!(
+                    "'{}' is not a valid hex prefix",
+                    prefix
+                )));
+            }
+
+            let threads = match matches.value_of("threads") {
+                Some(threads) => threads
+                    .parse()
+                    .ok()
+                    .filter(|threads| *threads > 0)
+                    .ok_or_else(|| {
+                        Error::InvalidArgument(format!("'{}' is not a valid thread count", threads))
+                    })?,
+                None => thread::available_parallelism().map_or(1, |threads| threads.get()),
+            };
+
+            crypto::generate_keypair_with_prefix(&prefix, threads)?
+        }
+        None => crypto::generate_keypair(),
     };
 
-    println!("{}{}", DAT_URL_PROTOCOL, hex::encode(public_key));
+    println!(
+        "{}{}",
+        DAT_URL_PROTOCOL,
+        hex::encode(keypair.public.as_bytes())
+    );
+    println!("Secret key: {}", hex::encode(&keypair.to_bytes()[..]));
 
-    // Build discovery key (hashed public key and name)
-    let discovery_key = crypto::generate_discovery_key(public_key);
+    Ok(())
+}
 
-    // Generate individual token to identify ourselves
-    let token = crypto::generate_random_token();
+fn inspect_key(matches: &ArgMatches) -> Result<(), Error> {
+    let public_key = parse_dat_url(matches.value_of("key").unwrap())?;
+    let discovery_key = crypto::generate_discovery_key(&public_key);
+
+    println!("URL: {}{}", DAT_URL_PROTOCOL, hex::encode(&public_key));
+    println!("Public key: {}", hex::encode(&public_key));
+    println!("Discovery key: {}", hex::encode(discovery_key.as_bytes()));
+
+    Ok(())
+}
+
+fn start() -> Result<(), Error> {
+    // Parse command-line arguments, help and version are printed right away
+    let matches = cli().get_matches_safe().map_err(|err| match err.kind {
+        ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => err.exit(),
+        _ => Error::Usage(err.message),
+    })?;
+
+    // Cloning can't be combined with any other mode
+    if let (true, Some(subcommand)) = (matches.is_present("clone"), matches.subcommand_name()) {
+        return Err(Error::InvalidArgument(format!(
+            "--clone can't be used together with the {} subcommand",
+            subcommand
+        )));
+    }
+
+    // Print completions for the requested shell and stop here
+    if let Some(completions) = matches.subcommand_matches("completions") {
+        let shell = value_t!(completions, "shell", Shell).unwrap();
+        cli().gen_completions_to("toy-hypercore", shell, &mut io::stdout());
+
+        return Ok(());
+    }
+
+    if let Some(keygen_matches) = matches.subcommand_matches("keygen") {
+        return keygen(keygen_matches);
+    }
+
+    if let Some(key_matches) = matches.subcommand_matches("key") {
+        if let Some(inspect_matches) = key_matches.subcommand_matches("inspect") {
+            return inspect_key(inspect_matches);
+        }
+    }
+
+    // Load configuration file, command-line arguments take precedence
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path)),
+        None => Config::load_default(),
+    }
+    .map_err(Error::Config)?;
+
+    if let Some(port) = parse_port(&matches)? {
+        config.port = port;
+    }
+
+    if let Some(lookup_timeout) = matches.value_of("lookup-timeout") {
+        config.lookup_timeout = lookup_timeout
+            .parse()
+            .ok()
+            .filter(|timeout| *timeout <= MAX_LOOKUP_TIMEOUT)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("'{}' is not a valid timeout", lookup_timeout))
+            })?;
+    }
+
+    if let Some(interface) = matches.value_of("interface") {
+        config.interface = Some(interface.parse().map_err(|_| {
+            Error::InvalidArgument(format!("'{}' is not a valid IPv4 address", interface))
+        })?);
+    }
+
+    if let Some(discovery) = matches.value_of("discovery") {
+        config.discovery = DiscoveryMode::parse(discovery).unwrap();
+    }
+
+    if let Some(broadcast_port) = matches.value_of("broadcast-port") {
+        config.broadcast_port = broadcast_port.parse().map_err(|_| {
+            Error::InvalidArgument(format!("'{}' is not a valid port", broadcast_port))
+        })?;
+    }
+
+    if let Some(relay_matches) = matches.subcommand_matches("relay") {
+        if let Some(port) = parse_port(relay_matches)? {
+            config.relay_port = port;
+        }
+    }
+
+    // Report problems with the configuration together with all other checks
+    if let Some(doctor_matches) = matches.subcommand_matches("doctor") {
+        return doctor::run(&config, doctor_matches.is_present("relay"));
+    }
+
+    config.validate().map_err(Error::InvalidConfig)?;
+
+    let chaos = parse_chaos(&matches)?;
 
     // Create event loop to drive the networking I/O
-    let mut core = Core::new().unwrap();
+    let mut core = Core::new().map_err(Error::Network)?;
     let handle = core.handle();
 
-    // Start main task
-    let main = run(handle.clone(), discovery_key.as_bytes(), token);
+    // Run as relay for other peers when requested
+    if matches.subcommand_matches("relay").is_some() {
+        println!("Relay listening on port {}", config.relay_port);
+
+        let relay = Relay::new(handle, config.relay_port);
+
+        return core.run(relay.listen()).map_err(Error::Network);
+    }
+
+    let backend: Rc<dyn DiscoveryBackend> = match config.discovery {
+        DiscoveryMode::Mdns => Rc::new(MdnsBackend::new(config.interface)),
+        DiscoveryMode::Broadcast => Rc::new(BroadcastBackend::new(config.broadcast_port)),
+    };
+
+    let mut node = NodeBuilder::new(handle)
+        .port(config.port)
+        .backend(backend)
+        .chaos(chaos)
+        .denylist(config.denylist.clone())
+        .build();
 
-    // ... and add it to event loop
-    core.run(main).unwrap();
+    // Create or clone hypercore depending on given arguments and print its
+    // dat:// URL
+    let feed = match matches.value_of("clone") {
+        Some(url) => node.clone_feed(&parse_dat_url(url)?)?,
+        None => node.create_feed()?,
+    };
+
+    println!("{}{}", DAT_URL_PROTOCOL, hex::encode(feed.public_key()));
+
+    // Start main task and add it to event loop
+    let main = run(&mut node, &config, matches.is_present("clone"));
+
+    core.run(main)
+}
+
+fn main() {
+    if let Err(err) = start() {
+        eprintln!("{}", err);
+        process::exit(err.exit_code());
+    }
 }
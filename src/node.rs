@@ -0,0 +1,289 @@
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::Keypair;
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use tokio_core::reactor::Handle;
+
+use crate::backend::{DiscoveryBackend, MdnsBackend};
+use crate::chaos::Chaos;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto;
+use crate::discovery::{Discovery, DiscoveryEvent};
+use crate::error::Error;
+use crate::task::TaskGroup;
+
+// @TODO Use port of listening TCP socket as soon as we have one
+pub const DEFAULT_PORT: u16 = 12345;
+
+type EventSender = mpsc::UnboundedSender<Result<NodeEvent, Error>>;
+type EventReceiver = mpsc::UnboundedReceiver<Result<NodeEvent, Error>>;
+
+pub struct NodeBuilder {
+    handle: Handle,
+    port: u16,
+    backend: Rc<dyn DiscoveryBackend>,
+    chaos: Chaos,
+    clock: Rc<dyn Clock>,
+    denylist: Vec<String>,
+}
+
+impl NodeBuilder {
+    pub fn new(handle: Handle) -> NodeBuilder {
+        NodeBuilder {
+            handle,
+            port: DEFAULT_PORT,
+            backend: Rc::new(MdnsBackend::new(None)),
+            chaos: Chaos::default(),
+            clock: Rc::new(SystemClock),
+            denylist: Vec::new(),
+        }
+    }
+
+    // Port other peers should connect to
+    pub fn port(mut self, port: u16) -> NodeBuilder {
+        self.port = port;
+        self
+    }
+
+    // How peers are found on the local network, mDNS is the default
+    pub fn backend(mut self, backend: Rc<dyn DiscoveryBackend>) -> NodeBuilder {
+        self.backend = backend;
+        self
+    }
+
+    // Simulate a flaky network by dropping and delaying incoming packets
+    pub fn chaos(mut self, chaos: Chaos) -> NodeBuilder {
+        self.chaos = chaos;
+        self
+    }
+
+    // Time source for discovery expiry and rate limits, see MockClock
+    pub fn clock(mut self, clock: Rc<dyn Clock>) -> NodeBuilder {
+        self.clock = clock;
+        self
+    }
+
+    // Hex encoded discovery keys we refuse to announce
+    pub fn denylist(mut self, denylist: Vec<String>) -> NodeBuilder {
+        self.denylist = denylist;
+        self
+    }
+
+    pub fn build(self) -> Node {
+        let (events_sender, events_receiver) = mpsc::unbounded();
+
+        Node {
+            tasks: TaskGroup::new(self.handle),
+            port: self.port,
+            backend: self.backend,
+            chaos: self.chaos,
+            clock: self.clock,
+            denylist: self.denylist,
+            // Generate individual token to identify ourselves
+            token: crypto::generate_random_token(),
+            events_sender,
+            events_receiver: Some(events_receiver),
+        }
+    }
+}
+
+// Something happened in the swarm of one of our feeds
+pub struct NodeEvent {
+    pub public_key: Vec<u8>,
+    pub event: DiscoveryEvent,
+}
+
+// Dropping the node stops all of its background work
+pub struct Node {
+    tasks: TaskGroup,
+    port: u16,
+    backend: Rc<dyn DiscoveryBackend>,
+    chaos: Chaos,
+    clock: Rc<dyn Clock>,
+    denylist: Vec<String>,
+    token: String,
+    events_sender: EventSender,
+    events_receiver: Option<EventReceiver>,
+}
+
+impl Node {
+    pub fn token(&self) -> String {
+        self.token.clone()
+    }
+
+    // Starts the lookup timeout, usually right after cloning a feed
+    pub fn lookup(&self, timeout: Duration) -> Lookup {
+        Lookup::new(self.clock.clone(), timeout)
+    }
+
+    // Creates a new writable feed and starts announcing it
+    pub fn create_feed(&self) -> Result<Feed, Error> {
+        let keypair = crypto::generate_keypair();
+        let public_key = keypair.public.as_bytes().to_vec();
+
+        self.open_feed(public_key, Some(keypair))
+    }
+
+    // Starts looking for peers sharing the feed with this public key
+    pub fn clone_feed(&self, public_key: &[u8]) -> Result<Feed, Error> {
+        self.open_feed(public_key.to_vec(), None)
+    }
+
+    // Returns events of all feeds, None when they were already taken
+    pub fn events(&mut self) -> Option<impl Stream<Item = NodeEvent, Error = Error>> {
+        self.events_receiver
+            .take()
+            .map(|receiver| receiver.then(|item| item.unwrap()))
+    }
+
+    fn open_feed(&self, public_key: Vec<u8>, keypair: Option<Keypair>) -> Result<Feed, Error> {
+        // Build discovery key (hashed public key and name)
+        let discovery_key = crypto::generate_discovery_key(&public_key)
+            .as_bytes()
+            .to_vec();
+
+        // Never announce keys the operator refuses to serve
+        let discovery_key_hex = hex::encode(&discovery_key);
+
+        if self
+            .denylist
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(&discovery_key_hex))
+        {
+            return Err(Error::DeniedKey(discovery_key_hex));
+        }
+
+        // Discover interesting peers and pass them on as node events
+        let mut discovery = Discovery::new(
+            self.tasks.spawner(),
+            &discovery_key,
+            self.port,
+            self.token.clone(),
+        );
+
+        discovery.set_backend(self.backend.clone());
+        discovery.set_chaos(self.chaos);
+        discovery.set_clock(self.clock.clone());
+
+        let public_key_clone = public_key.clone();
+
+        let events = discovery
+            .find_peers()
+            .flatten_stream()
+            .map(move |event| NodeEvent {
+                public_key: public_key_clone.clone(),
+                event,
+            })
+            .then(|result| Ok::<_, ()>(result.map_err(Error::Network)))
+            .forward(self.events_sender.clone().sink_map_err(|_| ()));
+
+        self.tasks.spawn(events.then(|_| Ok(())));
+
+        Ok(Feed {
+            public_key,
+            discovery_key,
+            keypair,
+        })
+    }
+}
+
+// Gives up looking for peers of a feed when nobody showed up in time, decided
+// by the clock of the node
+pub struct Lookup {
+    clock: Rc<dyn Clock>,
+    started_at: Instant,
+    timeout: Duration,
+    has_peers: bool,
+}
+
+impl Lookup {
+    pub fn new(clock: Rc<dyn Clock>, timeout: Duration) -> Lookup {
+        Lookup {
+            started_at: clock.now(),
+            clock,
+            timeout,
+            has_peers: false,
+        }
+    }
+
+    pub fn peer_found(&mut self) {
+        self.has_peers = true;
+    }
+
+    // Fails once the timeout passed without finding any peer
+    pub fn check(&self) -> Result<(), Error> {
+        if !self.has_peers && self.clock.elapsed(self.started_at) >= self.timeout {
+            Err(Error::NoPeersFound(self.timeout))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct Feed {
+    public_key: Vec<u8>,
+    discovery_key: Vec<u8>,
+    keypair: Option<Keypair>,
+}
+
+impl Feed {
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn discovery_key(&self) -> &[u8] {
+        &self.discovery_key
+    }
+
+    // Only feeds we created ourselves can be written to
+    pub fn is_writable(&self) -> bool {
+        self.keypair.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_core::reactor::Core;
+
+    use crate::clock::MockClock;
+
+    #[test]
+    fn events_can_only_be_taken_once() {
+        let core = Core::new().unwrap();
+        let mut node = NodeBuilder::new(core.handle()).build();
+
+        assert!(node.events().is_some());
+        assert!(node.events().is_none());
+    }
+
+    #[test]
+    fn lookup_fails_without_peers_after_timeout() {
+        let clock = MockClock::new();
+        let lookup = Lookup::new(Rc::new(clock.clone()), Duration::from_secs(30));
+
+        clock.advance(Duration::from_secs(29));
+        assert!(lookup.check().is_ok());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(
+            lookup.check(),
+            Err(Error::NoPeersFound(timeout)) if timeout == Duration::from_secs(30)
+        ));
+    }
+
+    #[test]
+    fn lookup_succeeds_once_a_peer_was_found() {
+        let clock = MockClock::new();
+        let mut lookup = Lookup::new(Rc::new(clock.clone()), Duration::from_secs(30));
+
+        clock.advance(Duration::from_secs(10));
+        lookup.peer_found();
+
+        clock.advance(Duration::from_secs(60));
+        assert!(lookup.check().is_ok());
+    }
+}
@@ -0,0 +1,158 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll, Stream};
+use tokio::io::{copy, read_exact, shutdown, AsyncRead};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Timeout};
+use tokio_core::reactor::Handle;
+
+use crate::task::TaskGroup;
+
+pub const RELAY_PORT: u16 = 3282;
+
+const TOKEN_LENGTH: usize = 32;
+
+// Peers need to send their token and meet their counterpart within this time
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_millis(30000);
+
+// Connections waiting for their counterpart at the same time, others get
+// closed right away
+const MAX_PENDING: usize = 1024;
+
+type Token = [u8; TOKEN_LENGTH];
+
+// Connections waiting for their counterpart, identified by rendezvous token.
+// The waiting connection gets handed over through the channel
+type Pending = Rc<RefCell<HashMap<Token, oneshot::Sender<TcpStream>>>>;
+
+pub struct Relay {
+    handle: Handle,
+    port: u16,
+}
+
+impl Relay {
+    pub fn new(handle: Handle, port: u16) -> Relay {
+        Relay { handle, port }
+    }
+
+    pub fn listen(&self) -> impl Future<Item = (), Error = Error> {
+        let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port);
+
+        // Connections are closed as soon as the relay stops listening
+        let tasks = TaskGroup::new(self.handle.clone());
+
+        let pending: Pending = Rc::new(RefCell::new(HashMap::new()));
+
+        futures::future::result(TcpListener::bind(&addr)).and_then(move |listener| {
+            listener.incoming().for_each(move |socket| {
+                let pending_clone = pending.clone();
+                let spawner = tasks.spawner();
+
+                // Every peer starts with sending the token it wants to meet at
+                let token = Timeout::new(read_exact(socket, [0; TOKEN_LENGTH]), RENDEZVOUS_TIMEOUT);
+
+                let rendezvous = token.map_err(|_| ()).and_then(move |(socket, token)| {
+                    // Hand over to the peer waiting at the same token, which
+                    // pipes their (encrypted) streams together
+                    let waiting = pending_clone.borrow_mut().remove(&token);
+
+                    let socket = match waiting {
+                        Some(other) => match other.send(socket) {
+                            Ok(()) => return Ok(()),
+                            // The waiting peer just gave up, wait in its place
+                            Err(socket) => socket,
+                        },
+                        None => socket,
+                    };
+
+                    if pending_clone.borrow().len() >= MAX_PENDING {
+                        return Ok(());
+                    }
+
+                    let (partner_sender, partner) = oneshot::channel();
+                    pending_clone.borrow_mut().insert(token, partner_sender);
+
+                    let wait = WaitForPartner {
+                        socket: Some(socket),
+                        partner,
+                        timeout: Delay::new(Instant::now() + RENDEZVOUS_TIMEOUT),
+                    };
+
+                    let spawner_clone = spawner.clone();
+
+                    spawner.spawn(wait.map(move |paired| match paired {
+                        Some((a, b)) => spawner_clone.spawn(Relay::pipe(a, b)),
+                        None => {
+                            // Forget about us unless a new peer took our place
+                            let mut pending = pending_clone.borrow_mut();
+
+                            if pending.get(&token).is_some_and(|other| other.is_canceled()) {
+                                pending.remove(&token);
+                            }
+                        }
+                    }));
+
+                    Ok(())
+                });
+
+                tasks.spawn(rendezvous);
+
+                Ok(())
+            })
+        })
+    }
+
+    fn pipe(a: TcpStream, b: TcpStream) -> impl Future<Item = (), Error = ()> {
+        let (a_reader, a_writer) = a.split();
+        let (b_reader, b_writer) = b.split();
+
+        // Close the other direction as soon as one side hangs up
+        let a_to_b = copy(a_reader, b_writer).and_then(|(_, _, writer)| shutdown(writer));
+        let b_to_a = copy(b_reader, a_writer).and_then(|(_, _, writer)| shutdown(writer));
+
+        a_to_b.join(b_to_a).then(|_| Ok(()))
+    }
+}
+
+// Resolves with both connections once the counterpart arrived, or with
+// nothing when the waiting peer hung up or the rendezvous timed out
+struct WaitForPartner {
+    socket: Option<TcpStream>,
+    partner: oneshot::Receiver<TcpStream>,
+    timeout: Delay,
+}
+
+impl Future for WaitForPartner {
+    type Item = Option<(TcpStream, TcpStream)>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, ()> {
+        if let Ok(Async::Ready(other)) = self.partner.poll() {
+            return Ok(Async::Ready(Some((self.socket.take().unwrap(), other))));
+        }
+
+        // Peek to notice a closed connection without consuming data the peer
+        // might already send for its counterpart
+        let is_closed = matches!(
+            self.socket.as_mut().unwrap().poll_peek(&mut [0]),
+            Ok(Async::Ready(0)) | Err(_)
+        );
+
+        let is_timed_out = !matches!(self.timeout.poll(), Ok(Async::NotReady));
+
+        if is_closed || is_timed_out {
+            // Nobody can hand over a connection to us anymore
+            self.partner.close();
+
+            return Ok(Async::Ready(None));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
@@ -0,0 +1,56 @@
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::Future;
+use tokio_core::reactor::Handle;
+
+// Owns futures spawned on the event loop, they all stop as soon as the group
+// gets dropped
+pub struct TaskGroup {
+    spawner: Spawner,
+    _stop: oneshot::Sender<()>,
+}
+
+impl TaskGroup {
+    pub fn new(handle: Handle) -> TaskGroup {
+        let (stop, stopped) = oneshot::channel();
+
+        TaskGroup {
+            spawner: Spawner {
+                handle,
+                stopped: stopped.shared(),
+            },
+            _stop: stop,
+        }
+    }
+
+    // Spawned tasks hold on to a spawner instead of the group itself, otherwise
+    // they would keep the group alive
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + 'static,
+    {
+        self.spawner.spawn(future);
+    }
+}
+
+#[derive(Clone)]
+pub struct Spawner {
+    handle: Handle,
+    stopped: Shared<oneshot::Receiver<()>>,
+}
+
+impl Spawner {
+    // Runs the future until it completes or its group is dropped
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + 'static,
+    {
+        let task = future.select2(self.stopped.clone()).then(|_| Ok(()));
+
+        self.handle.spawn(task);
+    }
+}